@@ -1,17 +1,83 @@
-use iced_x86::{Decoder, DecoderOptions, Formatter, GasFormatter, Instruction, IntelFormatter};
+use iced_x86::{
+    BlockEncoder, BlockEncoderOptions, CodeSize, Decoder, DecoderOptions, FlowControl, Formatter,
+    GasFormatter, Instruction, InstructionBlock, InstructionInfoFactory, IntelFormatter,
+    MasmFormatter, Mnemonic, NasmFormatter, OpKind, Register,
+};
 use rayon::prelude::*;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
 
 #[repr(u32)]
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Bitness {
+    X16 = 16,
+    X86 = 32,
     X64 = 64,
 }
 
+/// Assembly syntax to format decoded instructions in.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Syntax {
+    Intel,
+    Gas,
+    Masm,
+    Nasm,
+}
+
+/// A register read or written by an instruction, and how it was accessed.
+pub struct UsedRegister {
+    pub register: String,
+    pub access: String,
+}
+
+/// A memory operand read or written by an instruction.
+pub struct UsedMemory {
+    pub base: String,
+    pub index: String,
+    pub displacement: u64,
+    pub access: String,
+}
+
+/// Per-instruction register/memory/rflags data-flow, as reported by
+/// `InstructionInfoFactory`. Useful for taint tracking and dead-code detection
+/// without a second decode pass.
+pub struct InstrInfo {
+    pub ip: u64,
+    pub used_registers: Vec<UsedRegister>,
+    pub used_memory: Vec<UsedMemory>,
+    pub rflags_read: String,
+    pub rflags_written: String,
+}
+
+/// Where a basic block's control flow goes after its last instruction.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Edge {
+    /// Target is the start of a block within the decoded range.
+    Internal(u64),
+    /// Target lies outside the decoded byte range.
+    External(u64),
+    /// Target is an indirect branch, or lands outside any instruction boundary.
+    Unresolved,
+}
+
+/// A straight-line run of instructions with no internal branch targets.
+pub struct BasicBlock {
+    pub start_ip: u64,
+    pub end_ip: u64,
+    pub instructions: Vec<Instruction>,
+    pub successors: Vec<Edge>,
+}
+
+/// Control-flow graph reconstructed from branch/call/return targets.
+pub struct ControlFlowGraph {
+    pub blocks: Vec<BasicBlock>,
+}
+
 pub struct DisassemblerResult {
     pub assembly: Vec<String>,
     pub most_used_instructions: Vec<(String, usize)>,
     pub isa_extensions_used: Vec<String>,
+    pub instruction_info: Vec<InstrInfo>,
+    pub cfg: ControlFlowGraph,
 }
 
 pub fn disassemble(
@@ -19,21 +85,80 @@ pub fn disassemble(
     bitness: Bitness,
     rip: u64,
     use_binary: bool,
-    use_intel: bool,
+    syntax: Syntax,
+    strip_nops: bool,
 ) -> DisassemblerResult {
-    let instructions: Vec<Instruction> = {
+    let mut instructions: Vec<Instruction> = {
         let mut decoder = Decoder::with_ip(bitness as u32, bytes, rip, DecoderOptions::NONE);
         decoder.iter().collect()
     };
 
-    let assembly = extract_assembly(&instructions, bytes, rip, use_binary, use_intel);
+    // The CFG is built from the raw decoded stream before any stripping: it
+    // relies on block boundaries lining up with actually-decoded instructions,
+    // an invariant a cleanup pass like strip_nops would otherwise break.
+    let cfg = build_control_flow_graph(&instructions, rip, rip + bytes.len() as u64);
+
+    if strip_nops {
+        instructions.retain(|instruction| !is_semantic_nop(instruction));
+    }
+
+    let assembly = extract_assembly(&instructions, bytes, rip, bitness, use_binary, syntax);
     let most_used_instructions = extract_most_used_instructions(&instructions);
     let isa_extensions_used = extract_isa_extensions(&instructions);
+    let instruction_info = extract_instruction_info(&instructions);
 
     DisassemblerResult {
         assembly,
         most_used_instructions,
         isa_extensions_used,
+        instruction_info,
+        cfg,
+    }
+}
+
+/// Decodes `bytes` at `old_rip` and re-encodes it at `new_rip`, letting
+/// `BlockEncoder` fix up relative branches and RIP-relative memory operands.
+/// Useful for shellcode rebasing or code-cave patching without hand-fixing
+/// displacements.
+pub fn relocate(bytes: &[u8], bitness: Bitness, old_rip: u64, new_rip: u64) -> Result<Vec<u8>, String> {
+    let instructions: Vec<Instruction> = {
+        let mut decoder = Decoder::with_ip(bitness as u32, bytes, old_rip, DecoderOptions::NONE);
+        decoder.iter().collect()
+    };
+
+    let block = InstructionBlock::new(&instructions, new_rip);
+    BlockEncoder::encode(bitness as u32, block, BlockEncoderOptions::NONE)
+        .map(|result| result.code_buffer)
+        .map_err(|e| e.to_string())
+}
+
+/// True for instructions that have no observable effect: padding NOPs, and the
+/// usual compiler-emitted disguises for them (`xchg`/`mov` a register with
+/// itself, `lea reg,[reg+0]`).
+fn is_semantic_nop(instruction: &Instruction) -> bool {
+    // In 64-bit mode a 32-bit destination register is zero-extended, so
+    // `mov eax,eax` and the like do change the full register.
+    let zero_extends =
+        instruction.code_size() == CodeSize::Code64 && instruction.op0_register().is_gpr32();
+    match instruction.mnemonic() {
+        Mnemonic::Nop => true,
+        Mnemonic::Xchg | Mnemonic::Mov => {
+            instruction.op_count() == 2
+                && !zero_extends
+                && instruction.op0_kind() == OpKind::Register
+                && instruction.op1_kind() == OpKind::Register
+                && instruction.op0_register() == instruction.op1_register()
+        }
+        Mnemonic::Lea => {
+            instruction.op_count() == 2
+                && !zero_extends
+                && instruction.op0_kind() == OpKind::Register
+                && instruction.op1_kind() == OpKind::Memory
+                && instruction.memory_base() == instruction.op0_register()
+                && instruction.memory_index() == Register::None
+                && instruction.memory_displacement64() == 0
+        }
+        _ => false,
     }
 }
 
@@ -41,27 +166,52 @@ fn extract_assembly(
     instructions: &[Instruction],
     bytes: &[u8],
     rip: u64,
+    bitness: Bitness,
     use_binary: bool,
-    use_intel: bool,
+    syntax: Syntax,
 ) -> Vec<String> {
-    let width = if use_binary { 64 + 16 } else { 32 };
+    // Narrower modes decode shorter instructions on average, so the machine-code
+    // column doesn't need as much padding as full 64-bit encodings.
+    let hex_width = match bitness {
+        Bitness::X16 => 16,
+        Bitness::X86 => 24,
+        Bitness::X64 => 32,
+    };
+    let width = if use_binary { hex_width * 2 + 16 } else { hex_width };
     instructions
         .par_iter()
         .map(|&instruction| {
             let mut out = String::new();
-            if use_intel {
-                let mut formatter = IntelFormatter::new();
-                let options = formatter.options_mut();
-                options.set_uppercase_mnemonics(false);
-                options.set_first_operand_char_index(8);
-                formatter.format(&instruction, &mut out);
-            } else {
-                let mut formatter = GasFormatter::new();
-                let options = formatter.options_mut();
-                options.set_uppercase_mnemonics(false);
-                options.set_gas_show_mnemonic_size_suffix(true);
-                options.set_first_operand_char_index(8);
-                formatter.format(&instruction, &mut out);
+            match syntax {
+                Syntax::Intel => {
+                    let mut formatter = IntelFormatter::new();
+                    let options = formatter.options_mut();
+                    options.set_uppercase_mnemonics(false);
+                    options.set_first_operand_char_index(8);
+                    formatter.format(&instruction, &mut out);
+                }
+                Syntax::Gas => {
+                    let mut formatter = GasFormatter::new();
+                    let options = formatter.options_mut();
+                    options.set_uppercase_mnemonics(false);
+                    options.set_gas_show_mnemonic_size_suffix(true);
+                    options.set_first_operand_char_index(8);
+                    formatter.format(&instruction, &mut out);
+                }
+                Syntax::Masm => {
+                    let mut formatter = MasmFormatter::new();
+                    let options = formatter.options_mut();
+                    options.set_uppercase_mnemonics(false);
+                    options.set_first_operand_char_index(8);
+                    formatter.format(&instruction, &mut out);
+                }
+                Syntax::Nasm => {
+                    let mut formatter = NasmFormatter::new();
+                    let options = formatter.options_mut();
+                    options.set_uppercase_mnemonics(false);
+                    options.set_first_operand_char_index(8);
+                    formatter.format(&instruction, &mut out);
+                }
             };
 
             let mut line = if use_binary {
@@ -112,6 +262,183 @@ fn extract_most_used_instructions(instructions: &[Instruction]) -> Vec<(String,
     most_used
 }
 
+fn extract_instruction_info(instructions: &[Instruction]) -> Vec<InstrInfo> {
+    let mut factory = InstructionInfoFactory::new();
+    instructions
+        .iter()
+        .map(|instruction| {
+            let info = factory.info(instruction);
+
+            let used_registers = info
+                .used_registers()
+                .iter()
+                .map(|reg| UsedRegister {
+                    register: format!("{:?}", reg.register()),
+                    access: format!("{:?}", reg.access()),
+                })
+                .collect();
+
+            let used_memory = info
+                .used_memory()
+                .iter()
+                .map(|mem| UsedMemory {
+                    base: format!("{:?}", mem.base()),
+                    index: format!("{:?}", mem.index()),
+                    displacement: mem.displacement(),
+                    access: format!("{:?}", mem.access()),
+                })
+                .collect();
+
+            InstrInfo {
+                ip: instruction.ip(),
+                used_registers,
+                used_memory,
+                rflags_read: format!("{:?}", instruction.rflags_read()),
+                rflags_written: format!("{:?}", instruction.rflags_written()),
+            }
+        })
+        .collect()
+}
+
+/// Classifies instructions that end a basic block, per the algorithm's terminator set.
+fn is_block_terminator(flow_control: FlowControl) -> bool {
+    matches!(
+        flow_control,
+        FlowControl::UnconditionalBranch
+            | FlowControl::ConditionalBranch
+            | FlowControl::Return
+            | FlowControl::Call
+            | FlowControl::IndirectBranch
+            | FlowControl::IndirectCall
+    )
+}
+
+/// Resolves a direct near-branch target relative to the decoded byte range.
+/// A target only counts as `Internal` if it lands exactly on a decoded
+/// instruction's IP; `leaders` alone isn't enough since it can contain
+/// addresses that a branch merely points at without anything being decoded
+/// there (e.g. the middle of another instruction's encoding).
+fn resolve_branch_edge(instruction: &Instruction, range: (u64, u64), instruction_ips: &HashSet<u64>) -> Edge {
+    let target = instruction.near_branch_target();
+    if target < range.0 || target >= range.1 {
+        Edge::External(target)
+    } else if instruction_ips.contains(&target) {
+        Edge::Internal(target)
+    } else {
+        Edge::Unresolved
+    }
+}
+
+/// Resolves the fall-through successor of a block, if any instruction follows it.
+fn resolve_fallthrough_edge(
+    next_ip: u64,
+    range: (u64, u64),
+    instruction_ips: &HashSet<u64>,
+) -> Option<Edge> {
+    if next_ip >= range.1 {
+        None
+    } else if instruction_ips.contains(&next_ip) {
+        Some(Edge::Internal(next_ip))
+    } else {
+        Some(Edge::Unresolved)
+    }
+}
+
+fn build_control_flow_graph(instructions: &[Instruction], start: u64, end: u64) -> ControlFlowGraph {
+    if instructions.is_empty() {
+        return ControlFlowGraph { blocks: Vec::new() };
+    }
+    let range = (start, end);
+    let instruction_ips: HashSet<u64> = instructions.iter().map(|instr| instr.ip()).collect();
+
+    // First pass: collect leaders - the entry point, every in-range branch/call
+    // target, and every instruction following a block terminator. A target that
+    // doesn't land on a decoded instruction boundary is harmless here: the
+    // second pass only ever splits at real instruction IPs, and edge resolution
+    // re-checks `instruction_ips` before calling anything `Internal`.
+    let mut leaders = BTreeSet::new();
+    leaders.insert(instructions[0].ip());
+    for instruction in instructions {
+        match instruction.flow_control() {
+            FlowControl::UnconditionalBranch | FlowControl::ConditionalBranch | FlowControl::Call => {
+                let target = instruction.near_branch_target();
+                if target >= range.0 && target < range.1 {
+                    leaders.insert(target);
+                }
+            }
+            _ => {}
+        }
+        if is_block_terminator(instruction.flow_control()) {
+            let next_ip = instruction.next_ip();
+            if next_ip < range.1 {
+                leaders.insert(next_ip);
+            }
+        }
+    }
+
+    // Second pass: walk instructions in address order, starting a new block at
+    // each leader and closing it at each terminator. A block's start and end are
+    // read back from the first and last instruction collected into `current`.
+    let mut blocks = Vec::new();
+    let mut current: Vec<Instruction> = Vec::new();
+
+    let finish_block = |current: &mut Vec<Instruction>, successors: Vec<Edge>| -> BasicBlock {
+        let start_ip = current.first().unwrap().ip();
+        let end_ip = current.last().unwrap().next_ip();
+        BasicBlock {
+            start_ip,
+            end_ip,
+            instructions: std::mem::take(current),
+            successors,
+        }
+    };
+
+    for (index, &instruction) in instructions.iter().enumerate() {
+        if index > 0 && leaders.contains(&instruction.ip()) && !current.is_empty() {
+            // The block wasn't closed by a terminator - it just runs straight into
+            // the next leader - so control falls through to it, not a dead end.
+            blocks.push(finish_block(&mut current, vec![Edge::Internal(instruction.ip())]));
+        }
+        current.push(instruction);
+
+        let flow_control = instruction.flow_control();
+        if is_block_terminator(flow_control) {
+            let successors = match flow_control {
+                FlowControl::ConditionalBranch | FlowControl::Call => {
+                    let mut edges = vec![resolve_branch_edge(&instruction, range, &instruction_ips)];
+                    edges.extend(resolve_fallthrough_edge(
+                        instruction.next_ip(),
+                        range,
+                        &instruction_ips,
+                    ));
+                    edges
+                }
+                FlowControl::UnconditionalBranch => {
+                    vec![resolve_branch_edge(&instruction, range, &instruction_ips)]
+                }
+                FlowControl::IndirectBranch => vec![Edge::Unresolved],
+                FlowControl::IndirectCall => {
+                    let mut edges = vec![Edge::Unresolved];
+                    edges.extend(resolve_fallthrough_edge(
+                        instruction.next_ip(),
+                        range,
+                        &instruction_ips,
+                    ));
+                    edges
+                }
+                FlowControl::Return => Vec::new(),
+                _ => unreachable!("is_block_terminator only matches the arms above"),
+            };
+            blocks.push(finish_block(&mut current, successors));
+        }
+    }
+    if !current.is_empty() {
+        blocks.push(finish_block(&mut current, Vec::new()));
+    }
+
+    ControlFlowGraph { blocks }
+}
+
 fn extract_isa_extensions(instructions: &[Instruction]) -> Vec<String> {
     let mut result = Vec::new();
     for instruction in instructions {
@@ -124,3 +451,95 @@ fn extract_isa_extensions(instructions: &[Instruction]) -> Vec<String> {
     }
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conditional_branch_records_both_target_and_fallthrough() {
+        // nop; jnz short 4; nop; ret - the jnz targets the ret, falls through to the second nop.
+        let bytes = [0x90u8, 0x75, 0x01, 0x90, 0xC3];
+        let result = disassemble(&bytes, Bitness::X64, 0, false, Syntax::Nasm, false);
+
+        assert_eq!(result.cfg.blocks.len(), 3);
+        assert_eq!(result.cfg.blocks[0].successors, vec![Edge::Internal(4), Edge::Internal(3)]);
+    }
+
+    #[test]
+    fn misaligned_branch_target_is_unresolved() {
+        // jmp short 3, landing in the middle of the following xchg ax,ax encoding.
+        let bytes = [0xEBu8, 0x01, 0x66, 0x90];
+        let result = disassemble(&bytes, Bitness::X64, 0, false, Syntax::Nasm, false);
+
+        assert_eq!(result.cfg.blocks[0].successors, vec![Edge::Unresolved]);
+    }
+
+    #[test]
+    fn external_branch_target_is_recorded_as_external() {
+        // jmp short to an address past the end of the decoded range.
+        let bytes = [0xEBu8, 0x7E];
+        let result = disassemble(&bytes, Bitness::X64, 0, false, Syntax::Nasm, false);
+
+        assert_eq!(result.cfg.blocks[0].successors, vec![Edge::External(0x80)]);
+    }
+
+    #[test]
+    fn block_closed_by_a_leader_boundary_falls_through() {
+        // nop; nop; nop; jmp short -4, looping back into the middle of the nop run.
+        let bytes = [0x90u8, 0x90, 0x90, 0xEB, 0xFC];
+        let result = disassemble(&bytes, Bitness::X64, 0x1000, false, Syntax::Nasm, false);
+
+        // The loop target (0x1001) splits the nop run; the first block has no
+        // terminator of its own but must still fall through to the second.
+        assert_eq!(result.cfg.blocks[0].start_ip, 0x1000);
+        assert_eq!(result.cfg.blocks[0].end_ip, 0x1001);
+        assert_eq!(result.cfg.blocks[0].successors, vec![Edge::Internal(0x1001)]);
+    }
+
+    #[test]
+    fn indirect_call_terminates_its_block_with_an_unresolved_and_fallthrough_edge() {
+        // call rax; nop
+        let bytes = [0xFFu8, 0xD0, 0x90];
+        let result = disassemble(&bytes, Bitness::X64, 0, false, Syntax::Nasm, false);
+
+        assert_eq!(result.cfg.blocks.len(), 2);
+        assert_eq!(
+            result.cfg.blocks[0].successors,
+            vec![Edge::Unresolved, Edge::Internal(2)]
+        );
+    }
+
+    #[test]
+    fn strip_nops_does_not_corrupt_cfg_block_boundaries() {
+        // jmp short 2; nop; ret
+        let bytes = [0xEBu8, 0x00, 0x90, 0xC3];
+        let result = disassemble(&bytes, Bitness::X64, 0, false, Syntax::Nasm, true);
+
+        // The CFG still sees the nop the cleanup pass removed from `assembly`.
+        assert_eq!(result.cfg.blocks.len(), 2);
+        assert_eq!(result.cfg.blocks[1].instructions.len(), 2);
+        assert_eq!(result.assembly.len(), 2);
+    }
+
+    #[test]
+    fn relocate_preserves_rip_relative_target_address() {
+        // lea rax, [rip+0], pointing at the byte right after itself.
+        let bytes = [0x48u8, 0x8D, 0x05, 0x00, 0x00, 0x00, 0x00];
+        let old_rip = 0x1000u64;
+        let new_rip = 0x9000u64;
+
+        let relocated = relocate(&bytes, Bitness::X64, old_rip, new_rip).unwrap();
+
+        let original_target = {
+            let mut decoder = Decoder::with_ip(64, &bytes, old_rip, DecoderOptions::NONE);
+            decoder.decode().ip_rel_memory_address()
+        };
+        let relocated_target = {
+            let mut decoder = Decoder::with_ip(64, &relocated, new_rip, DecoderOptions::NONE);
+            decoder.decode().ip_rel_memory_address()
+        };
+
+        assert_eq!(relocated_target, original_target);
+    }
+}